@@ -1,15 +1,26 @@
 use axum::{
-    extract::Extension,
-    http::{HeaderMap, StatusCode},
+    body::Body,
+    extract::{ConnectInfo, Extension},
+    http::{header, HeaderMap, Response, StatusCode},
     response::IntoResponse,
     routing::{get, post},
     Router,
 };
-use rand::Rng;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+mod csrf_layer;
+mod store;
+use csrf_layer::{
+    csrf_cookie_header, extract_client_ip, generate_hmac_token, CsrfLayer, CsrfStrategy,
+    FORWARDED_FOR_HEADER, HMAC_SECRET_BYTES,
+};
+use store::{MemoryStore, SessionStore};
+
 /*
 How to test the code below:
 1- Open Postman
@@ -26,21 +37,65 @@ Then, execute the POST request. You have 30 seconds to do so until the CSRF toke
 const SECONDS_TIMEOUT: u64 = 30;
 const SESSION_TIMEOUT: Duration = Duration::new(SECONDS_TIMEOUT, 0);
 
-#[derive(Clone)]
+// Number of random bytes pulled from the CSPRNG for each token, i.e. its entropy
+// before base64 encoding. 32 bytes = 256 bits, comfortably above the ~128 bits
+// generally considered infeasible to brute-force or collide.
+const CSRF_TOKEN_BYTES: usize = 32;
+
 struct AppState {
-    sessions: Arc<Mutex<HashMap<String, Instant>>>, // In-memory session store
+    // Boxed so the backend (in-memory, Redis, ...) can be swapped without AppState
+    // itself, or anything holding an `Arc<AppState>`, needing to change.
+    sessions: Box<dyn SessionStore>,
+    // Which of `CsrfLayer`'s supported CSRF defenses `get_csrf_token` and the layer
+    // itself are enforcing. See `csrf_layer::CsrfStrategy`.
+    csrf_strategy: CsrfStrategy,
+    // HMAC key used to sign and verify tokens under `CsrfStrategy::StatelessHmac`.
+    // Generated fresh at startup, so restarting the process invalidates every
+    // outstanding token; unused by the other strategies.
+    csrf_secret: Vec<u8>,
+    // How long a `StatelessHmac` token stays valid after being issued. Unlike the
+    // other two strategies, this mode has no session store to hang a per-token
+    // timeout off of, so it needs its own setting; defaults to `SESSION_TIMEOUT`.
+    // Unused by the other strategies.
+    csrf_hmac_max_age: Duration,
+    // Whether a token issued by `get_csrf_token` is bound to the client IP it was
+    // issued to, and rejected by `CsrfLayer` if a later request presents it from a
+    // different IP. Off by default: it's the stronger posture, but breaks clients
+    // behind a NAT or proxy pool that rotates their apparent IP mid-session. Only
+    // affects `CsrfStrategy::SynchronizerToken`, the only mode with a session store
+    // to bind the IP in.
+    bind_client_ip: bool,
+    // Whether to trust `X-Forwarded-For` for the client IP instead of the TCP peer
+    // address. Only safe when a reverse proxy in front of this server overwrites the
+    // header itself; otherwise a client can set it to whatever IP it likes and defeat
+    // `bind_client_ip` entirely.
+    trust_forwarded_for: bool,
 }
 
 #[tokio::main]
 async fn main() {
-    // Create a session store. Arc = Atomic Reference Counting. Safe sharing of data across multiple threads.
-    // Arc only indicates that something will be shared, thus it is Thread Safe (it could be pretty much anything)
-    // Any data structure creates simple in-memory store of sessions, thus nothing persisted to SSD / HDD
-    // Anything other than memory store will need some custom implementation (File store, DB store, ...)
-    // IMPORTANT: This can only work when you have 1 backend server. If you have multiple, I suggest to use Redis and handle
-    // sessions through DB requests! Redis has TTL, which automatically expires sessions after a certain time.
+    // Create a session store. `MemoryStore` works great for a single backend
+    // process. If you're running more than one, swap this for
+    // `store::RedisStore::new("redis://127.0.0.1/", SESSION_TIMEOUT).await?` instead:
+    // Redis's own key TTL takes care of expiry across the whole pool, the same way
+    // `MemoryStore`'s cache already takes care of it for a single process.
+    let mut csrf_secret = vec![0u8; HMAC_SECRET_BYTES];
+    OsRng.fill_bytes(&mut csrf_secret);
+
     let app_state = Arc::new(AppState {
-        sessions: Arc::new(Mutex::new(HashMap::new())),
+        sessions: Box::new(MemoryStore::new(SESSION_TIMEOUT)),
+        // Swap for `CsrfStrategy::DoubleSubmitCookie` or `CsrfStrategy::StatelessHmac`
+        // to skip the session store lookup on check; the latter also drops the need
+        // for `sessions` and the cleanup task below entirely.
+        csrf_strategy: CsrfStrategy::SynchronizerToken,
+        csrf_secret,
+        // How long a StatelessHmac token remains valid; only reached if
+        // csrf_strategy above is switched to StatelessHmac.
+        csrf_hmac_max_age: SESSION_TIMEOUT,
+        // Flip to `true` (and set `trust_forwarded_for` if there's a reverse proxy
+        // in front) to bind issued tokens to the client IP that requested them.
+        bind_client_ip: false,
+        trust_forwarded_for: false,
     });
     
     // Build the router and its different paths
@@ -48,11 +103,16 @@ async fn main() {
         // route to /csrf-token, http get with get_csrf_token function call
         .route("/csrf-token", get(get_csrf_token))
         // route to /process, http post with process_form function call
-        .route("/process", post(check_csrf_token))
+        // note there is no per-route CSRF wiring here anymore: CsrfLayer below checks
+        // every unsafe-method request for the whole router before it reaches a handler
+        .route("/process", post(process_form))
         // 'layer' adds a functionality for all requests here. --> 'add functionality' are keywords here
         // Extension means that you are adding a shared object across all requests --> 'shared object' are keywords here
         // this essentially means that we are adding the store object to be accessible across all requests
-        .layer(Extension(app_state.clone()));
+        .layer(Extension(app_state.clone()))
+        // blanket CSRF protection: validates X-CSRF-Token on POST/PUT/PATCH/DELETE and
+        // skips GET/HEAD/OPTIONS automatically, rejecting with 403 before any handler runs
+        .layer(CsrfLayer::new(app_state.clone()));
 
 
     // Start the cleanup task
@@ -66,89 +126,100 @@ async fn main() {
 
     // Run the application on port 3000
     axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        // converts router object to MakeService (required by serve method)
-        .serve(app.into_make_service())
+        // converts router object to MakeService, carrying the TCP peer address as
+        // `ConnectInfo` so CsrfLayer (and get_csrf_token) can read it for IP binding
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
-// Generate a CSRF token
+// Generate a CSRF token. Uses the OS's CSPRNG (`OsRng`) to fill `CSRF_TOKEN_BYTES`
+// bytes (256 bits by default), then URL-safe base64-encodes them into an opaque
+// string. 256 bits of entropy from a CSPRNG makes the token infeasible to guess or
+// collide, unlike a single `u64` drawn from a non-cryptographic generator.
 fn generate_csrf_token() -> String {
-    let mut rng = rand::thread_rng();
-    let token: u64 = rng.gen();
-    token.to_string()
+    let mut bytes = [0u8; CSRF_TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
 }
 
 // Endpoint to get the CSRF token
 async fn get_csrf_token(
     Extension(store): Extension<Arc<AppState>>,
-) -> impl IntoResponse {
-    let token = generate_csrf_token();
-    let token_clone: String = token.clone();
-    let now = Instant::now();
-
-    // Need to lock the mutex (always) before performing anything on it
-    store.sessions.lock()
-                  .expect("The store could not lock for some reason")
-                  .insert(token, now);
-
-    // Return the CSRF token in the response
-    (StatusCode::OK, token_clone)
-}
-
-// Process form submissions
-async fn check_csrf_token(
-    Extension(store): Extension<Arc<AppState>>,
+    ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-) -> Result<StatusCode, (StatusCode, &'static str)> {
-    // Validate CSRF token
-    let request_token = headers.get("X-CSRF-Token").and_then(|h| h.to_str().ok()).map(|s| s.to_string());
-    // transforms the request_token option to string and sets default value to "" if option = none
-    let request_token_val: String = request_token.unwrap_or("".to_string());
-    // Passing a reference, no cloning needed
-    let is_stored_token: bool = store.sessions.lock()
-                                              .expect("Could not lock mutex")
-                                              .contains_key(&request_token_val);
-
-    if !is_stored_token {
-        return Err((StatusCode::FORBIDDEN, "Invalid CSRF token"));
+) -> impl IntoResponse {
+    if store.csrf_strategy == CsrfStrategy::StatelessHmac {
+        // No session-store bookkeeping needed: the token carries its own expiry and
+        // integrity check, signed with `csrf_secret`.
+        let token = generate_hmac_token(&store.csrf_secret);
+        return Response::builder()
+            .status(StatusCode::OK)
+            .body(Body::from(token))
+            .unwrap();
     }
 
-    // I initialize it here by default. At this point, we know the CSRF token is in the map, so last_activity
-    // is not going to be the instant time
-    let mut last_activity: Instant = Instant::now();
-    // Passing a reference, no cloning needed
-    if let Some(start_time_ref) = store.sessions.lock()
-                                                          .expect("Could not lock mutex")
-                                                          .get(&request_token_val) {
-        // `start_time_ref` is of type `&Instant`
-        last_activity = *start_time_ref; // Dereference to get the `Instant`
-    }
-    
-    let current_time = Instant::now();
+    let token = generate_csrf_token();
 
-    // Check if the session is expired
-    if current_time.duration_since(last_activity) > SESSION_TIMEOUT {
-        return Err((StatusCode::UNAUTHORIZED, "Session expired"));
-    }
+    let response = Response::builder().status(StatusCode::OK);
+    let response = if store.csrf_strategy == CsrfStrategy::DoubleSubmitCookie {
+        // Double-submit cookie: checking it is just comparing the header against
+        // this cookie (see `CsrfConfig::check`), so there's no session store to
+        // record the token in.
+        response.header(header::SET_COOKIE, csrf_cookie_header(&token))
+    } else {
+        let expiry = Instant::now() + SESSION_TIMEOUT;
+        let issuing_ip = store.bind_client_ip.then(|| {
+            extract_client_ip(
+                headers.get(FORWARDED_FOR_HEADER),
+                Some(peer_addr.ip()),
+                store.trust_forwarded_for,
+            )
+        }).flatten();
+
+        store.sessions.insert(token.clone(), expiry, issuing_ip).await;
+        response
+    };
+
+    // Return the CSRF token in the response body either way, for the
+    // synchronizer-token flow (or clients that just want to read the body).
+    response.body(Body::from(token)).unwrap()
+}
 
-    // Update last activity
-    store.sessions.lock().expect("Could not lock mutex")
-                         .insert(request_token_val, current_time);
-    
-    Ok(StatusCode::OK)
+// Process form submissions. CSRF validation no longer happens in here: by the time
+// this handler runs, CsrfLayer has already rejected any unsafe-method request that
+// didn't carry a valid, unexpired token, so this just does the "real" work.
+async fn process_form() -> StatusCode {
+    StatusCode::OK
 }
 
 
-// You wouldn't need to have a background process if you had a DB that handles TTL
-// AppState is the struct defined earlier
+// `remove_expired` is a no-op on every current backend now: `MemoryStore`'s cache
+// expires entries on its own via a background monitor task, and Redis already
+// expires keys on its own. Kept around as the extension point for a future backend
+// that needs an explicit sweep.
 async fn cleanup_sessions(state: Arc<AppState>) {
     loop {
-        {
-            let mut sessions = state.sessions.lock().unwrap();
-            let now = Instant::now();
-            sessions.retain(|_, &mut last_activity| now.duration_since(last_activity) < SESSION_TIMEOUT);
-        }
+        state.sessions.remove_expired().await;
         tokio::time::sleep(SESSION_TIMEOUT).await; // Run every SESSION_TIMEOUT seconds
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_csrf_token_has_full_entropy() {
+        let token = generate_csrf_token();
+        assert_eq!(
+            URL_SAFE_NO_PAD.decode(token).unwrap().len(),
+            CSRF_TOKEN_BYTES
+        );
+    }
+
+    #[test]
+    fn generate_csrf_token_does_not_collide() {
+        assert_ne!(generate_csrf_token(), generate_csrf_token());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,108 @@
+// The original single-process session store: a mutex-guarded hash map, replaced here
+// with `retainer`'s concurrent, natively-TTL'd cache. A `Mutex<HashMap<_>>` serializes
+// every request behind one lock and only reclaims memory once per `remove_expired`
+// sweep; `retainer::Cache` shards its locking internally and expires each entry
+// lazily on access, backed by its own lightweight background monitor task, so this
+// store no longer needs the hand-rolled `retain` loop at all. See `RedisStore` for
+// the multi-server case.
+use async_trait::async_trait;
+use retainer::Cache;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::{ip_matches, SessionStore};
+
+// The client IP a token was issued to, if IP binding is enabled. Expiry itself is
+// tracked by the cache, not stored alongside the value.
+#[derive(Clone, Copy)]
+struct Session {
+    ip: Option<IpAddr>,
+}
+
+pub struct MemoryStore {
+    cache: Arc<Cache<String, Session>>,
+    // How far forward `touch` slides a token's expiry.
+    timeout: Duration,
+}
+
+impl MemoryStore {
+    pub fn new(timeout: Duration) -> Self {
+        let cache = Arc::new(Cache::new());
+
+        // retainer's own cleanup: periodically sample a slice of entries and evict
+        // whichever fraction of them have expired, rather than one exhaustive sweep
+        // every `timeout`. Leaked onto its own task for the store's lifetime, same as
+        // `cleanup_sessions` used to be.
+        let monitor = cache.clone();
+        tokio::spawn(async move {
+            monitor.monitor(4, 0.25, Duration::from_secs(3)).await;
+        });
+
+        MemoryStore { cache, timeout }
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn insert(&self, token: String, expiry: Instant, ip: Option<IpAddr>) {
+        let ttl = expiry.saturating_duration_since(Instant::now());
+        self.cache.insert(token, Session { ip }, ttl).await;
+    }
+
+    async fn is_valid(&self, token: &str, requester_ip: Option<IpAddr>) -> bool {
+        match self.cache.get(&token.to_string()).await {
+            Some(session) => ip_matches(session.ip, requester_ip),
+            None => false,
+        }
+    }
+
+    async fn touch(&self, token: &str) {
+        // `retainer` has no in-place "reset this entry's TTL" call, so re-read the
+        // current value and reinsert it with a fresh expiry, preserving its bound IP.
+        let key = token.to_string();
+        if let Some(session) = self.cache.get(&key).await {
+            let session = *session;
+            self.cache.insert(key, session, self.timeout).await;
+        }
+    }
+
+    async fn remove_expired(&self) {
+        // Handled by the monitor task spawned in `new`; nothing to do here.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn is_valid_until_expiry_then_false() {
+        let store = MemoryStore::new(Duration::from_secs(30));
+        let expiry = Instant::now() + Duration::from_millis(50);
+        store.insert("token".to_string(), expiry, None).await;
+
+        assert!(store.is_valid("token", None).await);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!store.is_valid("token", None).await);
+    }
+
+    #[tokio::test]
+    async fn touch_slides_expiry_past_the_original_deadline() {
+        let timeout = Duration::from_millis(200);
+        let store = MemoryStore::new(timeout);
+        let expiry = Instant::now() + Duration::from_millis(50);
+        store.insert("token".to_string(), expiry, None).await;
+
+        // Touch before the short original expiry lapses, sliding it forward by
+        // `timeout` instead.
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        store.touch("token").await;
+
+        // The original 50ms expiry has now passed, but `touch` reset it to 200ms
+        // from when it ran, so the token should still be valid.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(store.is_valid("token", None).await);
+    }
+}
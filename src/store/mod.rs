@@ -0,0 +1,71 @@
+// A pluggable backend for where issued CSRF tokens live. The tutorial started out
+// with a single `Arc<Mutex<HashMap<String, Instant>>>`, which only works for a single
+// backend process. `SessionStore` lets that in-memory map and a Redis-backed store
+// sit behind the same interface, so `AppState` (and everything built on top of it,
+// like `CsrfLayer`) doesn't care which one is plugged in.
+use async_trait::async_trait;
+use std::net::IpAddr;
+use std::time::Instant;
+
+mod memory;
+mod redis_store;
+
+pub use memory::MemoryStore;
+// Alternate backend for running behind a pool of servers; not wired up by this
+// tutorial's own `main.rs`, which defaults to `MemoryStore`, but available to swap in.
+#[allow(unused_imports)]
+pub use redis_store::RedisStore;
+
+/// Whether a stored token's `ip` and the IP presenting it on check agree, i.e.
+/// whether the token is allowed to be used from `requester_ip`. Binding is opt-in:
+/// a token issued with `ip: None` (binding disabled) matches any requester.
+pub(crate) fn ip_matches(bound_ip: Option<IpAddr>, requester_ip: Option<IpAddr>) -> bool {
+    match bound_ip {
+        Some(bound) => Some(bound) == requester_ip,
+        None => true,
+    }
+}
+
+/// Storage for issued CSRF tokens and their expiry.
+///
+/// Implementations must be safe to share across request-handling tasks, since
+/// `AppState` hands out the same store to every request.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Record that `token` was issued and is valid until `expiry`, optionally bound
+    /// to the client IP it was issued to (see `ip_matches`).
+    async fn insert(&self, token: String, expiry: Instant, ip: Option<IpAddr>);
+
+    /// Whether `token` is known, hasn't passed its expiry, and (if it was bound to
+    /// an IP on issue) is being presented by that same `requester_ip`.
+    async fn is_valid(&self, token: &str, requester_ip: Option<IpAddr>) -> bool;
+
+    /// Slide a still-valid token's expiry forward by the store's configured
+    /// timeout, as if it had just been freshly issued. A no-op if the token isn't
+    /// known.
+    async fn touch(&self, token: &str);
+
+    /// Reclaim storage used by tokens that have passed their expiry. Stores backed
+    /// by something with native TTL support (like Redis) can make this a no-op,
+    /// since expiry is already handled by the backend itself.
+    async fn remove_expired(&self);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unbound_token_matches_any_requester() {
+        assert!(ip_matches(None, Some("1.2.3.4".parse().unwrap())));
+        assert!(ip_matches(None, None));
+    }
+
+    #[test]
+    fn bound_token_only_matches_the_same_ip() {
+        let bound = "1.2.3.4".parse().unwrap();
+        assert!(ip_matches(Some(bound), Some(bound)));
+        assert!(!ip_matches(Some(bound), Some("5.6.7.8".parse().unwrap())));
+        assert!(!ip_matches(Some(bound), None));
+    }
+}
@@ -0,0 +1,73 @@
+// A session store backed by Redis, so CSRF tokens are valid no matter which backend
+// server in a pool ends up handling the follow-up request. Redis's own key TTL does
+// the expiry bookkeeping, so there's no in-process cleanup loop needed for this
+// backend: `remove_expired` is a no-op.
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use super::{ip_matches, SessionStore};
+
+// Not constructed by this tutorial's own `main.rs` (see its comment on swapping
+// `MemoryStore` for this), so dead-code analysis can't see it's part of the public
+// pluggable-backend surface.
+#[allow(dead_code)]
+pub struct RedisStore {
+    // `MultiplexedConnection` is cheap to clone (clones just share the one
+    // underlying connection, multiplexing commands over it), so it's opened once
+    // here rather than per call, which would otherwise pay a fresh TCP handshake on
+    // every token issuance and CSRF check.
+    conn: MultiplexedConnection,
+    // How far forward `touch` slides a token's expiry, and the TTL newly inserted
+    // tokens get if their requested expiry is already in the past.
+    timeout: Duration,
+}
+
+impl RedisStore {
+    /// Connect to Redis at `redis_url` (e.g. `redis://127.0.0.1/`).
+    #[allow(dead_code)]
+    pub async fn new(redis_url: &str, timeout: Duration) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(redis_url)?;
+        let conn = client.get_multiplexed_async_connection().await?;
+        Ok(RedisStore { conn, timeout })
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisStore {
+    async fn insert(&self, token: String, expiry: Instant, ip: Option<IpAddr>) {
+        let ttl = expiry.saturating_duration_since(Instant::now());
+        let mut conn = self.conn.clone();
+        // The value carries the bound IP (or is empty when binding is disabled), so
+        // `is_valid` has something to compare the requester against. SETEX stores it
+        // with Redis's native TTL, which is what lets us drop the hand-rolled cleanup
+        // loop for this backend.
+        let value = ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let seconds = ttl.as_secs().max(1) as usize;
+        let _: Result<(), _> = conn.set_ex(token, value, seconds).await;
+    }
+
+    async fn is_valid(&self, token: &str, requester_ip: Option<IpAddr>) -> bool {
+        let mut conn = self.conn.clone();
+        let Ok(Some(value)) = conn.get::<_, Option<String>>(token).await else {
+            return false;
+        };
+        let bound_ip = if value.is_empty() {
+            None
+        } else {
+            value.parse().ok()
+        };
+        ip_matches(bound_ip, requester_ip)
+    }
+
+    async fn touch(&self, token: &str) {
+        let mut conn = self.conn.clone();
+        let _: Result<(), _> = conn.expire(token, self.timeout.as_secs() as usize).await;
+    }
+
+    async fn remove_expired(&self) {
+        // Redis already evicts keys as their TTL lapses, so there's nothing to do.
+    }
+}